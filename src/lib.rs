@@ -1,6 +1,6 @@
 //! This is a small utility crate that provides a Prometheus metrics endpoint as a Trillium handler.
 //! It responds to GET requests to "/metrics" with metrics from the provided registry, using
-//! text-format encoding.
+//! text-format encoding, or negotiates Protobuf encoding with [`negotiating_handler`].
 //!
 //! # Example:
 //!
@@ -15,53 +15,470 @@
 //! #   .with_stopper(stopper)
 //!     .run(handler);
 //! ```
-use prometheus::{Encoder, Registry, TextEncoder};
-use tracing::error;
-use trillium::{KnownHeaderName, Status};
+use std::{collections::HashSet, io::Write as _, time::Instant};
+
+use flate2::{write::GzEncoder, Compression};
+use prometheus::{
+    proto::MetricFamily, Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, ProtobufEncoder,
+    Registry, TextEncoder,
+};
+use tracing::{error, warn};
+use trillium::{Conn, KnownHeaderName, Method, Status};
 use trillium_router::Router;
 
-/// Creates a handler that responds to GET requests for "/metrics".
+/// Creates a handler that responds to GET requests for "/metrics", always replying in the
+/// Prometheus text exposition format.
+///
+/// Other methods on "/metrics" receive a `405 Method Not Allowed` with an `Allow: GET` header.
 pub fn text_format_handler(registry: Registry) -> Router {
-    Router::new().get("metrics", move |conn: trillium::Conn| {
+    Router::new().all("metrics", move |conn: Conn| {
+        let registry = registry.clone();
+        async move {
+            if conn.method() != Method::Get {
+                return method_not_allowed(conn);
+            }
+            encode_response(conn, &registry.gather(), TextEncoder::new())
+        }
+    })
+}
+
+/// Creates a handler that responds to GET requests for "/metrics" with metrics gathered from
+/// every registry in `registries`, encoded as a single text-format exposition document.
+///
+/// This is useful for processes that keep several registries, such as a primary registry and one
+/// per worker. Metric family names that are duplicated across registries are logged rather than
+/// merged, since the scraped families are concatenated as-is.
+///
+/// Other methods on "/metrics" receive a `405 Method Not Allowed` with an `Allow: GET` header.
+pub fn text_format_handler_multi(registries: Vec<Registry>) -> Router {
+    Router::new().all("metrics", move |conn: Conn| {
+        let registries = registries.clone();
+        async move {
+            if conn.method() != Method::Get {
+                return method_not_allowed(conn);
+            }
+            encode_response(conn, &gather_all(&registries), TextEncoder::new())
+        }
+    })
+}
+
+/// Gathers metric families from every registry in `registries` into a single collection,
+/// logging a warning for each metric family name that appears in more than one registry.
+fn gather_all(registries: &[Registry]) -> Vec<MetricFamily> {
+    let mut seen = HashSet::new();
+    let mut families = Vec::new();
+    for registry in registries {
+        for family in registry.gather() {
+            if !seen.insert(family.get_name().to_owned()) {
+                warn!(
+                    name = family.get_name(),
+                    "Duplicate metric family name across registries"
+                );
+            }
+            families.push(family);
+        }
+    }
+    families
+}
+
+/// Creates a handler that responds to GET requests for "/metrics", negotiating between the text
+/// exposition format and the Protobuf format based on the request's `Accept` header.
+///
+/// Requests that advertise `application/vnd.google.protobuf; ...; encoding=delimited` receive a
+/// Protobuf-encoded response; all other requests fall back to the text format.
+///
+/// Other methods on "/metrics" receive a `405 Method Not Allowed` with an `Allow: GET` header.
+pub fn negotiating_handler(registry: Registry) -> Router {
+    Router::new().all("metrics", move |conn: Conn| {
         let registry = registry.clone();
         async move {
-            let mut buffer = Vec::new();
+            if conn.method() != Method::Get {
+                return method_not_allowed(conn);
+            }
+            let families = registry.gather();
+            if wants_protobuf(conn.headers().get_str(KnownHeaderName::Accept)) {
+                encode_response(conn, &families, ProtobufEncoder::new())
+            } else {
+                encode_response(conn, &families, TextEncoder::new())
+            }
+        }
+    })
+}
+
+/// Returns true if the given `Accept` header value indicates the client wants the delimited
+/// Protobuf exposition format rather than the text format.
+fn wants_protobuf(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|media_range| {
+            let media_range = media_range.trim();
+            media_range.starts_with("application/vnd.google.protobuf")
+                && media_range.contains("encoding=delimited")
+        })
+    })
+}
+
+/// Creates a handler that responds to GET requests for "/metrics" in the text exposition format,
+/// registering three additional collectors into `registry` to record the scrapes themselves:
+///
+/// - `metrics_scrape_requests_total`, a counter of scrape requests handled
+/// - `metrics_scrape_duration_seconds`, a histogram of time spent encoding the response
+/// - `metrics_scrape_response_bytes`, a gauge of the size of the most recently encoded response
+///
+/// Other methods on "/metrics" receive a `405 Method Not Allowed` with an `Allow: GET` header.
+pub fn text_format_handler_instrumented(registry: Registry) -> Router {
+    let scrape_requests = IntCounter::new(
+        "metrics_scrape_requests_total",
+        "Total number of scrape requests handled by this endpoint",
+    )
+    .unwrap();
+    registry.register(Box::new(scrape_requests.clone())).unwrap();
+
+    let scrape_duration = Histogram::with_opts(HistogramOpts::new(
+        "metrics_scrape_duration_seconds",
+        "Time spent encoding the scrape response, in seconds",
+    ))
+    .unwrap();
+    registry
+        .register(Box::new(scrape_duration.clone()))
+        .unwrap();
+
+    let scrape_response_bytes = IntGauge::new(
+        "metrics_scrape_response_bytes",
+        "Size in bytes of the most recently encoded scrape response",
+    )
+    .unwrap();
+    registry
+        .register(Box::new(scrape_response_bytes.clone()))
+        .unwrap();
+
+    Router::new().all("metrics", move |conn: Conn| {
+        let registry = registry.clone();
+        let scrape_requests = scrape_requests.clone();
+        let scrape_duration = scrape_duration.clone();
+        let scrape_response_bytes = scrape_response_bytes.clone();
+        async move {
+            if conn.method() != Method::Get {
+                return method_not_allowed(conn);
+            }
+            scrape_requests.inc();
+
             let encoder = TextEncoder::new();
-            match encoder.encode(&registry.gather(), &mut buffer) {
-                Ok(()) => conn
-                    .with_response_header(
+            let families = registry.gather();
+            let start = Instant::now();
+            let result = try_encode(conn, &families, &encoder);
+            scrape_duration.observe(start.elapsed().as_secs_f64());
+
+            match result {
+                Ok((conn, buffer)) => {
+                    scrape_response_bytes.set(buffer.len() as i64);
+                    conn.with_response_header(
                         KnownHeaderName::ContentType,
                         encoder.format_type().to_owned(),
                     )
-                    .ok(buffer),
-                Err(error) => {
-                    error!(%error, "Failed to encode Prometheus metrics");
-                    conn.with_status(Status::InternalServerError)
+                    .ok(buffer)
                 }
+                Err(conn) => conn,
             }
         }
     })
 }
 
+/// Registers a process collector (CPU seconds, resident/virtual memory, open file descriptors,
+/// and start time) into `registry`, then creates a [`text_format_handler`] for it.
+///
+/// Requires this crate's `process` feature, available on the platforms the `prometheus` crate's
+/// process collector supports (currently Linux).
+#[cfg(feature = "process")]
+pub fn with_process_metrics(registry: Registry) -> Router {
+    let process_collector = prometheus::process_collector::ProcessCollector::for_self();
+    registry.register(Box::new(process_collector)).unwrap();
+    text_format_handler(registry)
+}
+
+/// Creates a handler that responds to GET requests for "/metrics" in the text exposition format,
+/// gzip-compressing the body and setting `Content-Encoding: gzip` when the request's
+/// `Accept-Encoding` header advertises gzip support; otherwise the body is returned as-is.
+///
+/// Other methods on "/metrics" receive a `405 Method Not Allowed` with an `Allow: GET` header.
+pub fn compressing_handler(registry: Registry) -> Router {
+    Router::new().all("metrics", move |conn: Conn| {
+        let registry = registry.clone();
+        async move {
+            if conn.method() != Method::Get {
+                return method_not_allowed(conn);
+            }
+            let encoder = TextEncoder::new();
+            match try_encode(conn, &registry.gather(), &encoder) {
+                Ok((conn, buffer)) => {
+                    let conn = conn.with_response_header(
+                        KnownHeaderName::ContentType,
+                        encoder.format_type().to_owned(),
+                    );
+                    respond_maybe_gzipped(conn, buffer)
+                }
+                Err(conn) => conn,
+            }
+        }
+    })
+}
+
+/// Gzip-compresses `body` and sets `Content-Encoding: gzip` when the conn's `Accept-Encoding`
+/// request header advertises gzip support; otherwise responds with `body` as-is.
+fn respond_maybe_gzipped(conn: Conn, body: Vec<u8>) -> Conn {
+    if !wants_gzip(conn.headers().get_str(KnownHeaderName::AcceptEncoding)) {
+        return conn.ok(body);
+    }
+
+    match gzip(&body) {
+        Ok(compressed) => conn
+            .with_response_header(KnownHeaderName::ContentEncoding, "gzip")
+            .ok(compressed),
+        Err(error) => {
+            error!(%error, "Failed to gzip-compress Prometheus metrics");
+            conn.ok(body)
+        }
+    }
+}
+
+/// Returns true if the given `Accept-Encoding` header value indicates the client accepts gzip.
+fn wants_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding.is_some_and(|accept_encoding| {
+        accept_encoding
+            .split(',')
+            .any(|coding| coding.split(';').next().unwrap_or("").trim() == "gzip")
+    })
+}
+
+fn gzip(buffer: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(buffer)?;
+    encoder.finish()
+}
+
+/// Responds `405 Method Not Allowed` with an `Allow: GET` header, for requests to "/metrics"
+/// other than GET.
+fn method_not_allowed(conn: Conn) -> Conn {
+    conn.with_response_header(KnownHeaderName::Allow, "GET")
+        .with_status(Status::MethodNotAllowed)
+}
+
+/// Encodes the given metric families with `encoder` into a buffer, returning it on success or
+/// responding `500` on `conn` on failure.
+fn try_encode(
+    conn: Conn,
+    families: &[MetricFamily],
+    encoder: &impl Encoder,
+) -> Result<(Conn, Vec<u8>), Conn> {
+    let mut buffer = Vec::new();
+    match encoder.encode(families, &mut buffer) {
+        Ok(()) => Ok((conn, buffer)),
+        Err(error) => {
+            error!(%error, "Failed to encode Prometheus metrics");
+            Err(conn.with_status(Status::InternalServerError))
+        }
+    }
+}
+
+/// Encodes the given metric families with `encoder` and writes them to the response body,
+/// setting `ContentType` from the encoder's format type.
+fn encode_response(conn: Conn, families: &[MetricFamily], encoder: impl Encoder) -> Conn {
+    match try_encode(conn, families, &encoder) {
+        Ok((conn, buffer)) => conn
+            .with_response_header(
+                KnownHeaderName::ContentType,
+                encoder.format_type().to_owned(),
+            )
+            .ok(buffer),
+        Err(conn) => conn,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use prometheus::{IntGauge, Registry};
-    use trillium_testing::{assert_response, prelude::get};
+    use prometheus::{IntGauge, ProtobufEncoder, Registry};
+    use trillium::{KnownHeaderName, Status};
+    use trillium_testing::{
+        assert_response,
+        prelude::{get, post},
+    };
 
-    use crate::text_format_handler;
+    use crate::{
+        compressing_handler, negotiating_handler, text_format_handler,
+        text_format_handler_instrumented, text_format_handler_multi,
+    };
+    #[cfg(feature = "process")]
+    use crate::with_process_metrics;
 
-    #[test]
-    fn text_format_encode() {
+    /// Builds a `Registry` containing a single `my_gauge` gauge set to 5.
+    fn test_registry() -> Registry {
         let registry = Registry::new();
         let gauge = IntGauge::new("my_gauge", "Test fixture").unwrap();
         gauge.set(5);
         registry.register(Box::new(gauge)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn text_format_encode() {
+        let handler = text_format_handler(test_registry());
+        assert_response!(
+            get("metrics").on(&handler),
+            200,
+            "# HELP my_gauge Test fixture\n# TYPE my_gauge gauge\nmy_gauge 5"
+        );
+    }
+
+    #[test]
+    fn text_format_encode_multi() {
+        let primary = Registry::new();
+        let primary_gauge = IntGauge::new("primary_gauge", "Test fixture").unwrap();
+        primary_gauge.set(5);
+        primary.register(Box::new(primary_gauge)).unwrap();
+
+        let worker = Registry::new();
+        let worker_gauge = IntGauge::new("worker_gauge", "Test fixture").unwrap();
+        worker_gauge.set(7);
+        worker.register(Box::new(worker_gauge)).unwrap();
+
+        let handler = text_format_handler_multi(vec![primary, worker]);
+        assert_response!(
+            get("metrics").on(&handler),
+            200,
+            "# HELP primary_gauge Test fixture\n# TYPE primary_gauge gauge\nprimary_gauge 5\n# HELP worker_gauge Test fixture\n# TYPE worker_gauge gauge\nworker_gauge 7"
+        );
+    }
+
+    #[test]
+    fn text_format_handler_instrumented_records_scrape_metrics() {
+        let registry = test_registry();
+        let handler = text_format_handler_instrumented(registry.clone());
+        get("metrics").on(&handler);
+
+        let families = registry.gather();
+        let names: Vec<_> = families.iter().map(|family| family.get_name()).collect();
+        assert!(names.contains(&"metrics_scrape_requests_total"));
+        assert!(names.contains(&"metrics_scrape_duration_seconds"));
+        assert!(names.contains(&"metrics_scrape_response_bytes"));
+
+        let requests_total = families
+            .iter()
+            .find(|family| family.get_name() == "metrics_scrape_requests_total")
+            .unwrap();
+        assert_eq!(
+            requests_total.get_metric()[0].get_counter().get_value(),
+            1.0
+        );
+
+        let scrape_duration = families
+            .iter()
+            .find(|family| family.get_name() == "metrics_scrape_duration_seconds")
+            .unwrap();
+        assert_eq!(
+            scrape_duration.get_metric()[0]
+                .get_histogram()
+                .get_sample_count(),
+            1
+        );
+
+        let scrape_response_bytes = families
+            .iter()
+            .find(|family| family.get_name() == "metrics_scrape_response_bytes")
+            .unwrap();
+        assert!(scrape_response_bytes.get_metric()[0].get_gauge().get_value() > 0.0);
+    }
+
+    #[test]
+    #[cfg(feature = "process")]
+    fn with_process_metrics_registers_process_collector() {
+        let registry = Registry::new();
+        let handler = with_process_metrics(registry.clone());
+
+        let conn = get("metrics").on(&handler);
+        assert_eq!(conn.status(), Some(Status::Ok));
+
+        let names: Vec<_> = registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_owned())
+            .collect();
+        assert!(names.iter().any(|name| name.starts_with("process_")));
+    }
+
+    #[test]
+    fn compressing_handler_no_gzip_requested() {
+        let handler = compressing_handler(test_registry());
+        assert_response!(
+            get("metrics").on(&handler),
+            200,
+            "# HELP my_gauge Test fixture\n# TYPE my_gauge gauge\nmy_gauge 5"
+        );
+    }
+
+    #[test]
+    fn compressing_handler_gzip_requested() {
+        use std::io::Read as _;
+
+        use flate2::read::GzDecoder;
+
+        let handler = compressing_handler(test_registry());
+        let mut conn = get("metrics")
+            .with_request_header(KnownHeaderName::AcceptEncoding, "gzip")
+            .on(&handler);
+
+        assert_eq!(conn.status(), Some(Status::Ok));
+        assert_eq!(
+            conn.response_headers().get_str(KnownHeaderName::ContentEncoding),
+            Some("gzip")
+        );
+
+        let body = conn.take_response_body().unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(
+            decoded,
+            "# HELP my_gauge Test fixture\n# TYPE my_gauge gauge\nmy_gauge 5"
+        );
+    }
 
+    #[test]
+    fn text_format_handler_rejects_non_get_methods() {
+        let registry = Registry::new();
         let handler = text_format_handler(registry);
+
+        let conn = post("metrics").on(&handler);
+        assert_eq!(conn.status(), Some(Status::MethodNotAllowed));
+        assert_eq!(
+            conn.response_headers().get_str(KnownHeaderName::Allow),
+            Some("GET")
+        );
+    }
+
+    #[test]
+    fn negotiating_handler_text_fallback() {
+        let handler = negotiating_handler(test_registry());
         assert_response!(
             get("metrics").on(&handler),
             200,
             "# HELP my_gauge Test fixture\n# TYPE my_gauge gauge\nmy_gauge 5"
         );
     }
+
+    #[test]
+    fn negotiating_handler_protobuf() {
+        let handler = negotiating_handler(test_registry());
+        let conn = get("metrics")
+            .with_request_header(
+                KnownHeaderName::Accept,
+                "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited",
+            )
+            .on(&handler);
+
+        assert_eq!(conn.status(), Some(Status::Ok));
+        assert_eq!(
+            conn.response_headers().get_str(KnownHeaderName::ContentType),
+            Some(ProtobufEncoder::new().format_type())
+        );
+    }
 }